@@ -0,0 +1,124 @@
+//! Configuration file (second CLI argument), controlling how yt-dlp is invoked and how
+//! feeds are transformed. Accepts either TOML or JSON, selected by the file extension.
+
+use anyhow::{bail, Context, Result};
+use serde_derive::Deserialize;
+
+/// Top-level configuration, loaded from the (optional) config file.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub yt_dlp: YtdlpConfig,
+    pub http: HttpConfig,
+    pub ttl: TtlConfig,
+    /// Videos at or below this length (and taller than wide) are treated as shorts.
+    pub short_max_seconds: u64,
+    /// Whether shorts should be dropped from the feed.
+    pub skip_shorts: bool,
+    /// Whether to strip the `updated` element so feed readers keep showing the
+    /// original publish date instead of the most recent refresh.
+    pub strip_updated: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            yt_dlp: YtdlpConfig::default(),
+            http: HttpConfig::default(),
+            ttl: TtlConfig::default(),
+            short_max_seconds: 180,
+            skip_shorts: true,
+            strip_updated: true,
+        }
+    }
+}
+
+/// Controls how long cached video metadata is trusted before it's revalidated, and how
+/// long unreferenced cache entries are kept around.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TtlConfig {
+    /// How often a recently-cached video is re-checked in the background.
+    pub fresh_recheck_secs: u64,
+    /// Once a video has been in the cache longer than this, we stop re-checking it --
+    /// premieres, edits and age-gating all tend to happen soon after upload.
+    pub fresh_window_secs: u64,
+    /// Entries that haven't been seen in a served feed for this long are evicted, so the
+    /// cache doesn't grow forever as channels publish new videos.
+    pub eviction_window_secs: u64,
+}
+
+impl Default for TtlConfig {
+    fn default() -> Self {
+        Self {
+            fresh_recheck_secs: 24 * 60 * 60,
+            fresh_window_secs: 7 * 24 * 60 * 60,
+            eviction_window_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Timeouts for outgoing HTTP requests (the RSS fetch and the native backend's InnerTube
+/// calls), so that a hung connection to YouTube can't wedge a rouille worker thread
+/// forever.
+///
+/// NOT IMPLEMENTED: a feature-gated choice of TLS roots (native system roots vs.
+/// bundled webpki roots, for minimal/musl builds) was also requested for this struct,
+/// but that requires Cargo feature flags wired into this crate's manifest, which
+/// doesn't exist in this checkout. Tracked as outstanding, not done.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout_secs: u64,
+    pub read_timeout_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 15,
+            read_timeout_secs: 15,
+        }
+    }
+}
+
+/// How to invoke yt-dlp.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct YtdlpConfig {
+    /// Path to (or name of) the yt-dlp executable.
+    pub executable_path: String,
+    /// Working directory to run yt-dlp in, e.g. so it picks up a `.netrc` or cookie jar.
+    pub working_directory: Option<String>,
+    /// Extra arguments appended to every yt-dlp invocation, e.g. `--cookies`, proxy args.
+    pub extra_args: Vec<String>,
+    /// Maximum number of attempts (including the first) before giving up on a video.
+    pub max_attempts: u32,
+}
+
+impl Default for YtdlpConfig {
+    fn default() -> Self {
+        Self {
+            executable_path: "yt-dlp".to_owned(),
+            working_directory: None,
+            extra_args: vec![],
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Load the config file, if one was given; otherwise fall back to defaults.
+pub fn load_config(config_file: Option<String>) -> Result<Config> {
+    let Some(config_file) = config_file else {
+        return Ok(Config::default());
+    };
+    let contents = std::fs::read_to_string(&config_file)
+        .with_context(|| format!("failed to read config file {config_file}"))?;
+    if config_file.ends_with(".toml") {
+        toml::from_str(&contents).context("failed to parse config file as TOML")
+    } else if config_file.ends_with(".json") {
+        serde_json::from_str(&contents).context("failed to parse config file as JSON")
+    } else {
+        bail!("config file must end in `.toml` or `.json`")
+    }
+}
@@ -1,25 +1,106 @@
 use std::io::{BufReader, BufWriter};
-use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, fs::File};
+use std::thread;
+use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+};
 use std::{env, fs};
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::prelude::*;
 use rouille::{Request, Response};
 use serde_derive::{Deserialize, Serialize};
 
+mod backend;
+mod config;
+use backend::MetadataBackend;
+use config::{Config, HttpConfig, TtlConfig};
+
+/// URL of a channel's YouTube RSS feed, templated with the `channel_id` query param.
+const YOUTUBE_FEED_URL: &str = "https://www.youtube.com/feeds/videos.xml";
+
+/// How much `last_served` must drift (in seconds) on a cache hit before we bother
+/// marking the state dirty and writing it to disk. Without this, every feed fetch
+/// would re-persist the entire cache just for touching this one timestamp.
+const LAST_SERVED_DEBOUNCE_SECS: i64 = 60 * 60;
+
 type VideoId = String;
 
+/// Whether (and how) a video is currently airing live.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+enum LiveStatus {
+    /// A regular, already-finished upload.
+    #[default]
+    NotLive,
+    /// A scheduled livestream or premiere that hasn't started yet.
+    Upcoming {
+        scheduled_start: DateTime<Utc>,
+    },
+    /// Currently streaming; final length isn't known yet.
+    Live,
+    /// A livestream/premiere that has finished; its length is now final.
+    Ended,
+}
+
+impl LiveStatus {
+    /// Whether this status means we should keep re-fetching the video instead of
+    /// trusting the cache, because the interesting data (the final length) isn't
+    /// in yet.
+    fn is_pending(&self) -> bool {
+        matches!(self, LiveStatus::Upcoming { .. } | LiveStatus::Live)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct YoutubeVideo {
-    /// Timestamp of this information.
+    /// Timestamp this information was last (re-)fetched.
     #[serde(with = "chrono::serde::ts_seconds")]
     timestamp: DateTime<Utc>,
+    /// Timestamp this video was first added to the cache. Used to decide when a video
+    /// is old enough that we stop bothering to revalidate it.
+    #[serde(with = "chrono::serde::ts_seconds", default = "Utc::now")]
+    first_seen: DateTime<Utc>,
+    /// Timestamp this video was last requested in a served feed. Entries that go
+    /// unrequested for too long are evicted from the cache.
+    #[serde(with = "chrono::serde::ts_seconds", default = "Utc::now")]
+    last_served: DateTime<Utc>,
     /// Length in seconds.
     length: u64,
     /// Is this a short?
     is_short: bool,
+    /// Live/premiere status of the video.
+    #[serde(default)]
+    live_status: LiveStatus,
+    /// A playable media URL for the podcast feed's `<enclosure>`, and when it expires.
+    #[serde(default)]
+    media_url: Option<CachedMediaUrl>,
+}
+
+/// A cached, resolved media URL, along with the enclosure metadata RSS podcast clients
+/// expect (`type`, `length`) and the timestamp it stops being valid.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CachedMediaUrl {
+    url: String,
+    content_type: String,
+    length_bytes: Option<u64>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    expires: DateTime<Utc>,
+}
+
+impl YoutubeVideo {
+    /// Whether this entry is stale and should be revalidated in the background. Videos
+    /// stay "fresh" (checked every `fresh_recheck_secs`) for `fresh_window_secs` after
+    /// first being cached; after that we trust the cached data indefinitely, since
+    /// premieres, edits and age-gating all tend to happen soon after upload.
+    fn is_stale(&self, ttl: &TtlConfig) -> bool {
+        let now = Utc::now();
+        if now - self.first_seen > chrono::Duration::seconds(ttl.fresh_window_secs as i64) {
+            return false;
+        }
+        now - self.timestamp > chrono::Duration::seconds(ttl.fresh_recheck_secs as i64)
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -31,6 +112,10 @@ struct State {
     /// The filename where the state is stored.
     #[serde(skip)]
     file: String,
+    /// Videos with a background refresh currently in flight, so that concurrent
+    /// requests observing the same stale entry don't each spawn their own refresh.
+    #[serde(skip)]
+    refreshing: HashSet<VideoId>,
 }
 
 fn load_state(state_file: String) -> Result<State> {
@@ -57,52 +142,159 @@ fn store_state(state: &Arc<Mutex<State>>) -> Result<()> {
     Ok(())
 }
 
-fn fetch_youtube_video_data(video_id: &str) -> Result<YoutubeVideo> {
-    #[derive(Deserialize)]
-    struct YtDlpJson {
-        duration: u64,
-        width: u64,
-        height: u64,
+fn get_youtube_video_data(
+    state: &Arc<Mutex<State>>,
+    backend: &Arc<dyn MetadataBackend>,
+    config: &Config,
+    video_id: &str,
+) -> Result<YoutubeVideo> {
+    // Check if we already have the video cached.
+    if let Some(mut video) = state.lock().unwrap().youtube_videos.get(video_id).cloned() {
+        // We assume that size and length of the video generally don't change, so we can
+        // use the cached data -- unless it's a live/upcoming video, whose final length
+        // isn't known yet and must be re-fetched until the broadcast ends.
+        if !video.live_status.is_pending() {
+            // Mark the entry as still wanted (so it isn't evicted) and serve it right
+            // away. Only flip `dirty` (and so trigger a disk write) once last_served
+            // has drifted by more than LAST_SERVED_DEBOUNCE_SECS -- otherwise every
+            // feed fetch for every channel would re-serialize and persist the entire
+            // cache, even when nothing about it actually changed.
+            let now = Utc::now();
+            {
+                let mut state = state.lock().unwrap();
+                if let Some(cached) = state.youtube_videos.get_mut(video_id) {
+                    if now - cached.last_served
+                        > chrono::Duration::seconds(LAST_SERVED_DEBOUNCE_SECS)
+                    {
+                        cached.last_served = now;
+                        state.dirty = true;
+                    }
+                }
+            }
+            video.last_served = now;
+            if video.is_stale(&config.ttl) {
+                spawn_background_refresh(state, backend, config, video_id);
+            }
+            return Ok(video);
+        }
     }
 
-    // Run yt-dlp and parse the JSON it produces.
-    let mut child = Command::new("yt-dlp")
-        .arg("--dump-json")
-        .arg(format!("https://www.youtube.com/watch?v={video_id}"))
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("failed to start yt-dlp; make sure it is installed")?;
-    let json: YtDlpJson = serde_json::from_reader(child.stdout.take().unwrap())
-        .context("failed to parse yt-dlp JSON")?;
-    if !child.wait()?.success() {
-        bail!("yt-dlp returned non-zero exit status");
-    }
+    let mut video_data = backend.fetch(video_id, config.short_max_seconds)?;
+    let now = Utc::now();
+    video_data.first_seen = state
+        .lock()
+        .unwrap()
+        .youtube_videos
+        .get(video_id)
+        .map(|v| v.first_seen)
+        .unwrap_or(now);
+    video_data.last_served = now;
 
-    // Convert the yt-dlp output into our own format.
-    let is_short = json.duration <= 180 && json.height >= json.width;
-    Ok(YoutubeVideo {
-        timestamp: Utc::now(),
-        length: json.duration,
-        is_short,
-    })
+    let mut state = state.lock().unwrap();
+    state
+        .youtube_videos
+        .insert(video_id.to_owned(), video_data.clone());
+    state.dirty = true;
+    Ok(video_data)
 }
 
-fn get_youtube_video_data(state: &Arc<Mutex<State>>, video_id: &str) -> Result<YoutubeVideo> {
-    // Check if we already have the video cached.
-    if let Some(video) = state.lock().unwrap().youtube_videos.get(video_id).cloned() {
-        // We assume that size and length of the video generally don't change,
-        // so we can use the cached data.
-        return Ok(video);
+/// Re-fetch a video's metadata in the background and update the cache, so feed
+/// responses stay fast while the data self-heals. A no-op if a refresh for this video
+/// is already in flight, so concurrent requests don't pile up redundant yt-dlp
+/// processes (and retries) hammering YouTube for the same video.
+fn spawn_background_refresh(
+    state: &Arc<Mutex<State>>,
+    backend: &Arc<dyn MetadataBackend>,
+    config: &Config,
+    video_id: &str,
+) {
+    {
+        let mut state = state.lock().unwrap();
+        if !state.refreshing.insert(video_id.to_owned()) {
+            return;
+        }
     }
+    let state = Arc::clone(state);
+    let backend = Arc::clone(backend);
+    let short_max_seconds = config.short_max_seconds;
+    let video_id = video_id.to_owned();
+    thread::spawn(move || {
+        match backend.fetch(&video_id, short_max_seconds) {
+            Ok(mut fresh) => {
+                let mut state = state.lock().unwrap();
+                if let Some(existing) = state.youtube_videos.get(&video_id) {
+                    fresh.first_seen = existing.first_seen;
+                    fresh.last_served = existing.last_served;
+                    fresh.media_url = existing.media_url.clone();
+                }
+                state.youtube_videos.insert(video_id.clone(), fresh);
+                state.dirty = true;
+            }
+            Err(err) => eprintln!("background refresh of {video_id} failed: {err}"),
+        }
+        state.lock().unwrap().refreshing.remove(&video_id);
+    });
+}
 
-    let video_data = fetch_youtube_video_data(video_id)?;
-
+/// Evict cache entries that haven't been requested in a served feed for a while, so the
+/// cache doesn't grow forever as channels publish new videos.
+fn evict_stale_entries(state: &Arc<Mutex<State>>, ttl: &TtlConfig) {
+    let now = Utc::now();
+    let window = chrono::Duration::seconds(ttl.eviction_window_secs as i64);
     let mut state = state.lock().unwrap();
+    let before = state.youtube_videos.len();
     state
         .youtube_videos
-        .insert(video_id.to_owned(), video_data.clone());
+        .retain(|_, video| now - video.last_served <= window);
+    if state.youtube_videos.len() != before {
+        state.dirty = true;
+    }
+}
+
+/// Resolve a playable media URL for the video, re-using the cached one as long as it
+/// hasn't expired yet.
+fn get_youtube_media_url(
+    state: &Arc<Mutex<State>>,
+    backend: &Arc<dyn MetadataBackend>,
+    video_id: &str,
+) -> Result<CachedMediaUrl> {
+    let cached = state
+        .lock()
+        .unwrap()
+        .youtube_videos
+        .get(video_id)
+        .and_then(|video| video.media_url.clone());
+    if let Some(cached) = &cached {
+        if cached.expires > Utc::now() {
+            return Ok(cached.clone());
+        }
+    }
+
+    let media_url = backend.resolve_media_url(video_id)?;
+    let cached = CachedMediaUrl {
+        url: media_url.url,
+        content_type: media_url.content_type,
+        length_bytes: media_url.length_bytes,
+        expires: media_url.expires,
+    };
+
+    let mut state = state.lock().unwrap();
+    if let Some(video) = state.youtube_videos.get_mut(video_id) {
+        video.media_url = Some(cached.clone());
+    }
     state.dirty = true;
-    Ok(video_data)
+    Ok(cached)
+}
+
+/// Fetch a channel's RSS feed from YouTube and parse it.
+fn fetch_channel_feed(feed_id: &str, http: &HttpConfig) -> Result<xmltree::Element> {
+    let feed_xml = attohttpc::get(YOUTUBE_FEED_URL)
+        .param("channel_id", feed_id)
+        .connect_timeout(Duration::from_secs(http.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(http.read_timeout_secs))
+        .send()
+        .context("failed to fetch RSS feed from YouTube")?;
+    xmltree::Element::parse(feed_xml).context("failed to parse RSS feed from YouTube")
 }
 
 fn format_duration(seconds: u64) -> String {
@@ -115,18 +307,18 @@ fn format_duration(seconds: u64) -> String {
     }
 }
 
-fn handle_youtube_feed(state: &Arc<Mutex<State>>, request: &Request) -> Result<Response> {
+fn handle_youtube_feed(
+    state: &Arc<Mutex<State>>,
+    backend: &Arc<dyn MetadataBackend>,
+    config: &Config,
+    request: &Request,
+) -> Result<Response> {
     let feed_id = request
         .get_param("channel_id")
         .ok_or_else(|| anyhow!("channel_id param missing"))?;
 
     // Fetch feed from youtube.
-    let feed_xml = attohttpc::get("https://www.youtube.com/feeds/videos.xml")
-        .param("channel_id", &feed_id)
-        .send()
-        .context("failed to fetch RSS feed from YouTube")?;
-    let mut feed =
-        xmltree::Element::parse(feed_xml).context("failed to parse RSS feed from YouTube")?;
+    let mut feed = fetch_channel_feed(&feed_id, &config.http)?;
 
     // Take all the entries from the feed, and collect (some of) them in modified form.
     let mut entries = vec![];
@@ -140,18 +332,24 @@ fn handle_youtube_feed(state: &Arc<Mutex<State>>, request: &Request) -> Result<R
             .get_child("title")
             .and_then(|e| e.get_text())
             .ok_or_else(|| anyhow!("videoId element missing"))?;
-        let video_data = get_youtube_video_data(state, &video_id)?;
+        let video_data = get_youtube_video_data(state, backend, config, &video_id)?;
 
         // Skip shorts.
-        if video_data.is_short {
+        if config.skip_shorts && video_data.is_short {
             continue;
         }
 
         // Update title.
-        let title = format!(
-            "{title} ({duration})",
-            duration = format_duration(video_data.length)
-        );
+        let title = match video_data.live_status {
+            LiveStatus::Live => format!("[LIVE] {title}"),
+            LiveStatus::Upcoming { scheduled_start } => {
+                format!("[Premiere {}] {title}", scheduled_start.format("%H:%M"))
+            }
+            LiveStatus::NotLive | LiveStatus::Ended => format!(
+                "{title} ({duration})",
+                duration = format_duration(video_data.length)
+            ),
+        };
         let title_elem = entry
             .get_mut_child("title")
             .ok_or_else(|| anyhow!("title element missing"))?;
@@ -159,7 +357,9 @@ fn handle_youtube_feed(state: &Arc<Mutex<State>>, request: &Request) -> Result<R
 
         // Remove "updated" so that the videos keep their original dates.
         // (Thunderbird displays the "updated" date instead of the "published" one.)
-        while let Some(_) = entry.take_child("updated") {}
+        if config.strip_updated {
+            while let Some(_) = entry.take_child("updated") {}
+        }
 
         entries.push(entry);
     }
@@ -168,7 +368,9 @@ fn handle_youtube_feed(state: &Arc<Mutex<State>>, request: &Request) -> Result<R
         feed.children.push(xmltree::XMLNode::Element(entry));
     }
 
-    // Store cached state.
+    // Evict cache entries for videos no longer appearing in any served feed, and
+    // store the (possibly now-dirty) cached state.
+    evict_stale_entries(state, &config.ttl);
     store_state(state).context("failed to store persistent state")?;
 
     // Turn this into XML again.
@@ -186,16 +388,127 @@ fn handle_youtube_feed(state: &Arc<Mutex<State>>, request: &Request) -> Result<R
     Ok(Response::from_data("text/xml", output))
 }
 
+/// Build an `xmltree::Element` with the given name and text content.
+fn text_element(name: &str, text: String) -> xmltree::Element {
+    let mut elem = xmltree::Element::new(name);
+    elem.children = vec![xmltree::XMLNode::Text(text)];
+    elem
+}
+
+fn handle_youtube_podcast_feed(
+    state: &Arc<Mutex<State>>,
+    backend: &Arc<dyn MetadataBackend>,
+    config: &Config,
+    request: &Request,
+) -> Result<Response> {
+    let feed_id = request
+        .get_param("channel_id")
+        .ok_or_else(|| anyhow!("channel_id param missing"))?;
+
+    let mut feed = fetch_channel_feed(&feed_id, &config.http)?;
+    let feed_title = feed
+        .get_child("title")
+        .and_then(|e| e.get_text())
+        .map(|t| t.into_owned())
+        .unwrap_or_else(|| feed_id.clone());
+
+    let mut channel = xmltree::Element::new("channel");
+    channel.children.push(xmltree::XMLNode::Element(text_element(
+        "title",
+        feed_title,
+    )));
+
+    while let Some(entry) = feed.take_child("entry") {
+        let video_id = entry
+            .get_child("videoId")
+            .and_then(|e| e.get_text())
+            .ok_or_else(|| anyhow!("videoId element missing"))?;
+        let title = entry
+            .get_child("title")
+            .and_then(|e| e.get_text())
+            .ok_or_else(|| anyhow!("videoId element missing"))?
+            .into_owned();
+        let video_data = get_youtube_video_data(state, backend, config, &video_id)?;
+
+        // Skip shorts; they don't make sense in a podcast player.
+        if config.skip_shorts && video_data.is_short {
+            continue;
+        }
+        // Live/upcoming videos don't have a stable media URL yet.
+        if video_data.live_status.is_pending() {
+            continue;
+        }
+
+        let media_url = get_youtube_media_url(state, backend, &video_id)?;
+
+        let mut item = xmltree::Element::new("item");
+        item.children
+            .push(xmltree::XMLNode::Element(text_element("title", title)));
+        let mut enclosure = xmltree::Element::new("enclosure");
+        enclosure.attributes.insert("url".to_owned(), media_url.url);
+        enclosure
+            .attributes
+            .insert("type".to_owned(), media_url.content_type);
+        enclosure.attributes.insert(
+            "length".to_owned(),
+            media_url.length_bytes.unwrap_or(0).to_string(),
+        );
+        item.children
+            .push(xmltree::XMLNode::Element(enclosure));
+        item.children.push(xmltree::XMLNode::Element(text_element(
+            "itunes:duration",
+            format_duration(video_data.length),
+        )));
+
+        channel.children.push(xmltree::XMLNode::Element(item));
+    }
+
+    // Evict cache entries for videos no longer appearing in any served feed, and
+    // store the (possibly now-dirty) cached state.
+    evict_stale_entries(state, &config.ttl);
+    store_state(state).context("failed to store persistent state")?;
+
+    let mut rss = xmltree::Element::new("rss");
+    rss.attributes.insert("version".to_owned(), "2.0".to_owned());
+    rss.attributes.insert(
+        "xmlns:itunes".to_owned(),
+        "http://www.itunes.com/dtds/podcast-1.0.dtd".to_owned(),
+    );
+    rss.children.push(xmltree::XMLNode::Element(channel));
+
+    let mut output: Vec<u8> = vec![];
+    rss.write_with_config(
+        &mut output,
+        xmltree::EmitterConfig {
+            perform_indent: true,
+            ..Default::default()
+        },
+    )
+    .context("failed to serialize podcast RSS feed")?;
+    output.push(b'\n'); // trailing newline is nice for testing
+
+    Ok(Response::from_data("text/xml", output))
+}
+
 fn main() -> Result<()> {
     let state_file = env::args()
         .nth(1)
         .ok_or_else(|| anyhow!("state file name must be passed as first argument"))?;
+    let config_file = env::args().nth(2);
     let state = Arc::new(Mutex::new(
         load_state(state_file).context("failed to load persistent state")?,
     ));
+    let config = config::load_config(config_file).context("failed to load config file")?;
+    let backend: Arc<dyn MetadataBackend> =
+        Arc::new(backend::default_backend(config.yt_dlp.clone(), config.http.clone()));
     rouille::start_server("127.0.0.1:12380", move |request: &Request| {
         let response = match &*request.url() {
-            "/www.youtube.com/feeds/videos.xml" => handle_youtube_feed(&state, request),
+            "/www.youtube.com/feeds/videos.xml" => {
+                handle_youtube_feed(&state, &backend, &config, request)
+            }
+            "/www.youtube.com/feeds/podcast.xml" => {
+                handle_youtube_podcast_feed(&state, &backend, &config, request)
+            }
             url => Ok(Response::text(format!("endpoint not found: {url}\n")).with_status_code(404)),
         };
         response.unwrap_or_else(|err| {
@@ -204,3 +517,57 @@ fn main() -> Result<()> {
         })
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video_at(first_seen: DateTime<Utc>, timestamp: DateTime<Utc>) -> YoutubeVideo {
+        YoutubeVideo {
+            timestamp,
+            first_seen,
+            last_served: Utc::now(),
+            length: 0,
+            is_short: false,
+            live_status: LiveStatus::NotLive,
+            media_url: None,
+        }
+    }
+
+    fn ttl() -> TtlConfig {
+        TtlConfig {
+            fresh_recheck_secs: 60,
+            fresh_window_secs: 3600,
+            eviction_window_secs: 86400,
+        }
+    }
+
+    #[test]
+    fn not_stale_within_recheck_interval() {
+        let now = Utc::now();
+        let video = video_at(now, now);
+        assert!(!video.is_stale(&ttl()));
+    }
+
+    #[test]
+    fn stale_once_recheck_interval_elapses_within_fresh_window() {
+        let now = Utc::now();
+        let video = video_at(
+            now - chrono::Duration::minutes(30),
+            now - chrono::Duration::minutes(2),
+        );
+        assert!(video.is_stale(&ttl()));
+    }
+
+    #[test]
+    fn not_stale_once_past_fresh_window() {
+        let now = Utc::now();
+        // First seen well outside fresh_window_secs; even though the last check was
+        // also long ago, we've stopped bothering to revalidate.
+        let video = video_at(
+            now - chrono::Duration::hours(2),
+            now - chrono::Duration::hours(2),
+        );
+        assert!(!video.is_stale(&ttl()));
+    }
+}